@@ -1,78 +1,231 @@
-use colored::*;
+use crossbeam_channel::{Receiver as CrossbeamReceiver, Select, Sender as CrossbeamSender};
 use holochain_core::{
     action::{Action, ActionWrapper},
     network::direct_message::DirectMessage,
     nucleus::ZomeFnCall,
     signal::{Signal, SignalReceiver},
 };
-use neon::{context::Context, prelude::*};
+use neon::{context::Context, declare_types, prelude::*};
 use std::{
     cell::RefCell,
     collections::HashMap,
     sync::{
-        mpsc::{Receiver, RecvTimeoutError, SyncSender},
-        Arc, Mutex,
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc, Mutex, Weak,
     },
-    time::Duration,
+    thread,
+    time::{Duration, Instant},
 };
 
 type ControlSender = SyncSender<ControlMsg>;
 type ControlReceiver = Receiver<ControlMsg>;
 
+/// How long a `CallFxChecker` will wait for its conditions to be satisfied
+/// before giving up and timing out. Every checker gets this same budget;
+/// there is no per-call override.
+const DEFAULT_CALL_TIMEOUT_MS: u64 = 60_000;
+
 /// Possible messages used to influence the behavior of the CallBlockingTask
-/// Currently the only action needed is to stop it, triggering its callback
 pub enum ControlMsg {
+    /// All conditions for a call were satisfied; resolve the Promise.
     Stop,
+    /// The call's deadline passed before all conditions were satisfied.
+    Timeout {
+        timeout_ms: u64,
+        num_conditions: usize,
+    },
+    /// The call's `CancellationToken` (or one of its ancestors) was cancelled.
+    Cancelled,
+}
+
+/// The shared state behind a `CancellationToken`: whether it's been
+/// cancelled, who to notify when it is, and which child tokens to cascade
+/// the cancellation to.
+#[derive(Default)]
+struct CancellationState {
+    cancelled: bool,
+    notify: Vec<ControlSender>,
+    children: Vec<CancellationToken>,
+}
+
+/// A node in a tree of cancellation tokens, modeled after structured
+/// cancellation trees in async runtimes: cancelling a token cancels every
+/// child (and grandchild, ...) derived from it, and is idempotent. A child
+/// created after its parent was already cancelled is cancelled immediately.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Mutex<CancellationState>>,
+    /// The parent's state, so a completed/cancelled child can remove
+    /// itself from the parent's `children` list instead of leaking there
+    /// for the life of the parent.
+    parent: Option<Weak<Mutex<CancellationState>>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Derive a child token. If this token is already cancelled, the child
+    /// is cancelled immediately.
+    pub fn child(&self) -> Self {
+        let child = Self {
+            inner: Arc::new(Mutex::new(CancellationState::default())),
+            parent: Some(Arc::downgrade(&self.inner)),
+        };
+        // `child.cancel()` ends by locking its parent (this token's
+        // `inner`) to detach itself, so the lock below must be released
+        // before it's called, not held across it.
+        let already_cancelled = {
+            let mut state = self.inner.lock().unwrap();
+            if state.cancelled {
+                true
+            } else {
+                state.children.push(child.clone());
+                false
+            }
+        };
+        if already_cancelled {
+            child.cancel();
+        }
+        child
+    }
+
+    /// Remove this token from its parent's `children` list. Called once a
+    /// token's checker is done with it (normally or via cancellation) so a
+    /// long-running container's root token doesn't accumulate a dangling
+    /// entry per call it has ever serviced.
+    fn detach(&self) {
+        if let Some(parent) = self.parent.as_ref().and_then(Weak::upgrade) {
+            let mut state = parent.lock().unwrap();
+            state.children.retain(|child| !Arc::ptr_eq(&child.inner, &self.inner));
+        }
+    }
+
+    /// Ask to be sent `ControlMsg::Cancelled` if/when this token is cancelled.
+    /// If it's already cancelled, the message is sent immediately.
+    pub fn notify(&self, tx: ControlSender) {
+        let mut state = self.inner.lock().unwrap();
+        if state.cancelled {
+            let _ = tx.send(ControlMsg::Cancelled);
+        } else {
+            state.notify.push(tx);
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.lock().unwrap().cancelled
+    }
+
+    /// Cancel this token, notifying all registered listeners and cascading
+    /// to all child tokens. Idempotent: cancelling twice is a no-op.
+    pub fn cancel(&self) {
+        // Each drained child's own `cancel()` calls its `detach()`, which
+        // locks its parent -- i.e. this token's `inner`. That lock must be
+        // released before we cascade into the children, or a thread that
+        // already holds it here deadlocks trying to re-acquire it below.
+        let children = {
+            let mut state = self.inner.lock().unwrap();
+            if state.cancelled {
+                return;
+            }
+            state.cancelled = true;
+            for tx in state.notify.drain(..) {
+                let _ = tx.send(ControlMsg::Cancelled);
+            }
+            state.children.drain(..).collect::<Vec<_>>()
+        };
+        for child in children {
+            child.cancel();
+        }
+        self.detach();
+    }
 }
 
 /// A predicate function which examines an ActionWrapper to see if it is
 /// the one it's looking for
 type CallFxCondition = Box<Fn(&ActionWrapper) -> bool + 'static + Send>;
 
+/// A human-readable snapshot of one in-flight `CallFxChecker`, for the JS
+/// test harness to inspect when a test is stuck instead of reading
+/// `println!` noise.
+#[derive(Debug, Clone)]
+pub struct CallMetrics {
+    pub zome: String,
+    pub function: String,
+    pub num_conditions_outstanding: usize,
+    pub num_conditions_satisfied: usize,
+    pub age_ms: u128,
+    /// Human-readable description of each condition still pending.
+    pub pending_conditions: Vec<String>,
+}
+
+/// Aggregate counters across all of a `Waiter`'s active checkers.
+#[derive(Debug, Clone, Default)]
+pub struct WaiterMetrics {
+    pub num_active_checkers: usize,
+    pub num_conditions_outstanding: usize,
+    pub oldest_pending_call_age_ms: u128,
+    pub calls: Vec<CallMetrics>,
+    /// Set when a `Trigger` was rejected as invalid for the current
+    /// `CheckerState`, so test code can assert on it instead of scraping
+    /// colored `println!` output.
+    pub last_invalid_trigger: Option<String>,
+    /// The `MainBackgroundTask`'s current lifecycle phase: `"running"`,
+    /// `"cancelling_lingering_checkers"`, `"stopped"`, or `"shut_down"`.
+    pub lifecycle: String,
+}
+
 /// A set of closures, each of which checks for a certain condition to be met
-/// (usually for a certain action to be seen). When the condition specified by the closure
-/// is met, that closure is removed from the set of checks.
+/// (usually for a certain action to be seen), together with a human-readable
+/// label describing what it's waiting for. When the condition specified by
+/// the closure is met, that closure is removed from the set of checks.
 ///
 /// When the set of checks goes from non-empty to empty, send a message via `tx`
 /// to the `CallBlockingTask` on the other side
 struct CallFxChecker {
     tx: ControlSender,
-    conditions: Vec<CallFxCondition>,
+    conditions: Vec<(String, CallFxCondition)>,
+    timeout_ms: u64,
+    deadline: Instant,
+    token: CancellationToken,
+    zome: String,
+    function: String,
+    created_at: Instant,
+    num_satisfied: usize,
 }
 
 impl CallFxChecker {
-    pub fn new(tx: ControlSender) -> Self {
+    /// `ZomeFnCall` carries no per-call timeout override, so every checker
+    /// gets the fixed `DEFAULT_CALL_TIMEOUT_MS` budget.
+    pub fn new(tx: ControlSender, token: CancellationToken, zome: String, function: String) -> Self {
+        let timeout_ms = DEFAULT_CALL_TIMEOUT_MS;
+        token.notify(tx.clone());
         Self {
             tx,
             conditions: Vec::new(),
+            timeout_ms,
+            deadline: Instant::now() + Duration::from_millis(timeout_ms),
+            token,
+            zome,
+            function,
+            created_at: Instant::now(),
+            num_satisfied: 0,
         }
     }
 
-    pub fn add<F>(&mut self, f: F) -> ()
+    pub fn add<F>(&mut self, label: impl Into<String>, f: F) -> ()
     where
         F: Fn(&ActionWrapper) -> bool + 'static + Send,
     {
-        self.conditions.push(Box::new(f));
-        println!(
-            "\n*** Condition {}: {} -> {}",
-            "ADDED".green(),
-            self.conditions.len() - 1,
-            self.conditions.len()
-        );
+        self.conditions.push((label.into(), Box::new(f)));
     }
 
     pub fn run_checks(&mut self, aw: &ActionWrapper) -> bool {
         let was_empty = self.conditions.is_empty();
         let size = self.conditions.len();
-        self.conditions.retain(|condition| !condition(aw));
-        if size != self.conditions.len() {
-            println!(
-                "\n*** Condition {}: {} -> {}",
-                "REMOVED".red(),
-                size,
-                size - 1
-            );
-        }
+        self.conditions.retain(|(_, condition)| !condition(aw));
+        self.num_satisfied += size - self.conditions.len();
         if self.conditions.is_empty() && !was_empty {
             self.stop();
             return false;
@@ -81,47 +234,378 @@ impl CallFxChecker {
         }
     }
 
+    /// A point-in-time snapshot of this checker's progress.
+    pub fn metrics(&self) -> CallMetrics {
+        CallMetrics {
+            zome: self.zome.clone(),
+            function: self.function.clone(),
+            num_conditions_outstanding: self.conditions.len(),
+            num_conditions_satisfied: self.num_satisfied,
+            age_ms: self.created_at.elapsed().as_millis(),
+            pending_conditions: self
+                .conditions
+                .iter()
+                .map(|(label, _)| label.clone())
+                .collect(),
+        }
+    }
+
     pub fn shutdown(&mut self) {
         self.conditions.clear();
         self.stop();
     }
 
+    /// Give up on the remaining conditions because the deadline has passed,
+    /// notifying the JS side with a `Timeout` rather than a `Stop`.
+    pub fn timeout(&mut self) {
+        let num_conditions = self.conditions.len();
+        self.conditions.clear();
+        self.tx
+            .send(ControlMsg::Timeout {
+                timeout_ms: self.timeout_ms,
+                num_conditions,
+            })
+            .unwrap();
+        self.token.detach();
+    }
+
+    /// Cancel this checker individually, e.g. because the originating
+    /// `ZomeFnCall` is known to have errored. Clears conditions and
+    /// notifies the `CallBlockingTask` via the `CancellationToken`.
+    pub fn cancel(&mut self) {
+        self.conditions.clear();
+        self.token.cancel();
+    }
+
     fn stop(&mut self) {
         self.tx.send(ControlMsg::Stop).unwrap();
+        self.token.detach();
     }
 }
 
-/// A simple Task that blocks until it receives `ControlMsg::Stop`.
-/// This is used to trigger a JS Promise resolution when a ZomeFnCall's
-/// side effects have all completed.
+/// The result of waiting for a `CallFxChecker` to finish, used to decide
+/// how `CallBlockingTask::complete` should settle the JS Promise.
+enum ControlOutcome {
+    Completed,
+    TimedOut { timeout_ms: u64, num_conditions: usize },
+    Cancelled,
+}
+
+/// A simple Task that blocks until it receives `ControlMsg::Stop`,
+/// `ControlMsg::Timeout` or `ControlMsg::Cancelled` (a closed channel counts
+/// as `Stop`). This is used to trigger a JS Promise resolution (or
+/// rejection) when a ZomeFnCall's side effects have all completed (or the
+/// wait has timed out or been cancelled).
 pub struct CallBlockingTask {
     pub rx: ControlReceiver,
 }
 
 impl Task for CallBlockingTask {
-    type Output = ();
+    type Output = ControlOutcome;
     type Error = String;
     type JsEvent = JsUndefined;
 
-    fn perform(&self) -> Result<(), String> {
-        while let Ok(sig) = self.rx.recv() {
-            match sig {
-                ControlMsg::Stop => break,
+    fn perform(&self) -> Result<ControlOutcome, String> {
+        loop {
+            match self.rx.recv() {
+                Ok(ControlMsg::Stop) => break Ok(ControlOutcome::Completed),
+                Ok(ControlMsg::Timeout {
+                    timeout_ms,
+                    num_conditions,
+                }) => {
+                    break Ok(ControlOutcome::TimedOut {
+                        timeout_ms,
+                        num_conditions,
+                    })
+                }
+                Ok(ControlMsg::Cancelled) => break Ok(ControlOutcome::Cancelled),
+                Err(_) => break Ok(ControlOutcome::Completed),
             }
         }
-        Ok(())
     }
 
-    fn complete(self, mut cx: TaskContext, result: Result<(), String>) -> JsResult<JsUndefined> {
-        result.map(|_| cx.undefined()).or_else(|e| {
-            let error_string = cx.string(format!("unable to initialize habitat: {}", e));
-            cx.throw(error_string)
-        })
+    fn complete(
+        self,
+        mut cx: TaskContext,
+        result: Result<ControlOutcome, String>,
+    ) -> JsResult<JsUndefined> {
+        match result {
+            Err(e) => {
+                let error_string = cx.string(format!("unable to initialize habitat: {}", e));
+                cx.throw(error_string)
+            }
+            Ok(ControlOutcome::Completed) => Ok(cx.undefined()),
+            Ok(ControlOutcome::TimedOut {
+                timeout_ms,
+                num_conditions,
+            }) => {
+                let error_string = cx.string(format!(
+                    "zome call side effects did not settle within {} ms: {} conditions outstanding",
+                    timeout_ms, num_conditions
+                ));
+                cx.throw(error_string)
+            }
+            Ok(ControlOutcome::Cancelled) => {
+                let error_string = cx.string("zome call wait cancelled");
+                cx.throw(error_string)
+            }
+        }
+    }
+}
+
+/// The lifecycle of the `Waiter`'s signal processing, modeled explicitly so
+/// that pause/resume/flush commands from the test harness have well-defined
+/// transitions instead of being bolted on as ad-hoc flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckerState {
+    Started,
+    Paused,
+    Flushing,
+    Stopped,
+}
+
+/// Commands which drive the `CheckerState` machine, sent in from the
+/// JS-facing side of the test harness.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Pause,
+    Resume,
+    FlushStart,
+    FlushStop,
+    Stop,
+}
+
+type TriggerSender = SyncSender<Trigger>;
+type TriggerReceiver = Receiver<Trigger>;
+
+/// Returned by `advance` when a `Trigger` doesn't make sense for the
+/// current `CheckerState`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTransition(pub CheckerState, pub Trigger);
+
+/// Compute the next `CheckerState` for a given `Trigger`, or reject the
+/// transition if it isn't valid from the current state.
+pub fn advance(state: CheckerState, trigger: Trigger) -> Result<CheckerState, InvalidTransition> {
+    use CheckerState::*;
+    use Trigger::*;
+    match (state, trigger) {
+        (Started, Pause) => Ok(Paused),
+        (Paused, Resume) => Ok(Started),
+        (Started, FlushStart) | (Paused, FlushStart) => Ok(Flushing),
+        (Flushing, FlushStop) => Ok(Started),
+        (Started, Stop) | (Paused, Stop) | (Flushing, Stop) => Ok(Stopped),
+        _ => Err(InvalidTransition(state, trigger)),
+    }
+}
+
+type SignalEventReceiver = CrossbeamReceiver<Signal>;
+type ShutdownSender = CrossbeamSender<()>;
+type ShutdownReceiver = CrossbeamReceiver<()>;
+
+/// A cloneable handle for controlling a running `MainBackgroundTask` from
+/// the JS-facing side of the test harness: pausing/resuming/flushing the
+/// signal Waiter, or shutting the task down immediately.
+#[derive(Clone)]
+pub struct BackgroundTaskHandle {
+    trigger_tx: TriggerSender,
+    shutdown_tx: ShutdownSender,
+    metrics: Arc<Mutex<WaiterMetrics>>,
+}
+
+impl BackgroundTaskHandle {
+    pub fn pause(&self) {
+        let _ = self.trigger_tx.send(Trigger::Pause);
+    }
+
+    pub fn resume(&self) {
+        let _ = self.trigger_tx.send(Trigger::Resume);
+    }
+
+    pub fn flush(&self) {
+        let _ = self.trigger_tx.send(Trigger::FlushStart);
+    }
+
+    /// Leave `Flushing` and resume normal signal processing. Without this,
+    /// a Waiter that's been flushed has no way back to `Started` and every
+    /// later trigger (e.g. a `pause()`) is silently rejected as invalid.
+    pub fn flush_stop(&self) {
+        let _ = self.trigger_tx.send(Trigger::FlushStop);
+    }
+
+    /// Stop the `MainBackgroundTask` loop immediately, rather than waiting
+    /// for it to notice a polled flag. Sent on its own channel so it's
+    /// picked up by the next `Select` regardless of how many Signals are
+    /// already queued ahead of it.
+    pub fn shutdown(&self) {
+        let _ = self.shutdown_tx.send(());
+    }
+
+    /// A synchronous snapshot of the Waiter's live metrics, safe to call
+    /// from the JS thread without waiting on the background task.
+    pub fn metrics_snapshot(&self) -> WaiterMetrics {
+        self.metrics.lock().unwrap().clone()
     }
+
+    /// Build the JS-facing `JsBackgroundTaskHandle` wrapping this live
+    /// handle. `declare_types!`'s `init` runs with only the JS-supplied
+    /// constructor arguments in scope, and `BackgroundTaskHandle` (channels,
+    /// an `Arc<Mutex<_>>`) isn't a JS value that could be passed as one, so
+    /// it's handed across via `PENDING_BACKGROUND_TASK_HANDLE` instead: stash
+    /// it here immediately before construction, and `init` claims it.
+    pub fn into_js<'a, C: Context<'a>>(self, cx: &mut C) -> JsResult<'a, JsBackgroundTaskHandle> {
+        PENDING_BACKGROUND_TASK_HANDLE.with(|cell| *cell.borrow_mut() = Some(self));
+        JsBackgroundTaskHandle::new(cx, Vec::new())
+    }
+}
+
+thread_local! {
+    /// See `BackgroundTaskHandle::into_js`.
+    static PENDING_BACKGROUND_TASK_HANDLE: RefCell<Option<BackgroundTaskHandle>> = RefCell::new(None);
 }
 
-fn log(msg: &str) {
-    println!("{}:\n{}\n", "(((LOG)))".bold(), msg);
+/// Convert a `WaiterMetrics` snapshot into the JS object shape consumed by
+/// the test harness: `{ numActiveCheckers, numConditionsOutstanding,
+/// oldestPendingCallAgeMs, lastInvalidTrigger, lifecycle, calls: [{ zome,
+/// function, numConditionsOutstanding, numConditionsSatisfied, ageMs,
+/// pendingConditions }, ...] }`. Called from the neon-exported metrics
+/// binding so test code can assert on or log exactly which side effects a
+/// call is still waiting for, or what a `MainBackgroundTask` is doing,
+/// instead of scraping colored `println!` output.
+pub fn metrics_to_js<'a, C: Context<'a>>(
+    cx: &mut C,
+    metrics: &WaiterMetrics,
+) -> JsResult<'a, JsObject> {
+    let obj = cx.empty_object();
+
+    let num_active_checkers = cx.number(metrics.num_active_checkers as f64);
+    obj.set(cx, "numActiveCheckers", num_active_checkers)?;
+
+    let num_conditions_outstanding = cx.number(metrics.num_conditions_outstanding as f64);
+    obj.set(cx, "numConditionsOutstanding", num_conditions_outstanding)?;
+
+    let oldest_pending_call_age_ms = cx.number(metrics.oldest_pending_call_age_ms as f64);
+    obj.set(cx, "oldestPendingCallAgeMs", oldest_pending_call_age_ms)?;
+
+    match &metrics.last_invalid_trigger {
+        Some(message) => {
+            let message = cx.string(message);
+            obj.set(cx, "lastInvalidTrigger", message)?;
+        }
+        None => {
+            let null = cx.null();
+            obj.set(cx, "lastInvalidTrigger", null)?;
+        }
+    }
+
+    let lifecycle = cx.string(&metrics.lifecycle);
+    obj.set(cx, "lifecycle", lifecycle)?;
+
+    let calls = JsArray::new(cx, metrics.calls.len() as u32);
+    for (i, call) in metrics.calls.iter().enumerate() {
+        let call_obj = cx.empty_object();
+
+        let zome = cx.string(&call.zome);
+        call_obj.set(cx, "zome", zome)?;
+
+        let function = cx.string(&call.function);
+        call_obj.set(cx, "function", function)?;
+
+        let num_conditions_outstanding = cx.number(call.num_conditions_outstanding as f64);
+        call_obj.set(cx, "numConditionsOutstanding", num_conditions_outstanding)?;
+
+        let num_conditions_satisfied = cx.number(call.num_conditions_satisfied as f64);
+        call_obj.set(cx, "numConditionsSatisfied", num_conditions_satisfied)?;
+
+        let age_ms = cx.number(call.age_ms as f64);
+        call_obj.set(cx, "ageMs", age_ms)?;
+
+        let pending_conditions = JsArray::new(cx, call.pending_conditions.len() as u32);
+        for (j, label) in call.pending_conditions.iter().enumerate() {
+            let label = cx.string(label);
+            pending_conditions.set(cx, j as u32, label)?;
+        }
+        call_obj.set(cx, "pendingConditions", pending_conditions)?;
+
+        calls.set(cx, i as u32, call_obj)?;
+    }
+    obj.set(cx, "calls", calls)?;
+
+    Ok(obj)
+}
+
+// Registered as `BackgroundTaskHandle` in this addon's `register_module!`
+// (in lib.rs, alongside the other exported classes/functions). A
+// TestContainer's JS wrapper gets its instance via
+// `BackgroundTaskHandle::into_js`, called right after `MainBackgroundTask::new`
+// runs, so it always wraps that task's live handle rather than a stand-in.
+declare_types! {
+    /// The JS-facing class handed out alongside a TestContainer's
+    /// `MainBackgroundTask`, giving test code `pause()`/`resume()`/
+    /// `flush()`/`flushStop()`/`shutdown()` entry points onto the running
+    /// signal Waiter. Always constructed via `BackgroundTaskHandle::into_js`;
+    /// see `PENDING_BACKGROUND_TASK_HANDLE` for how `init` gets hold of it.
+    pub class JsBackgroundTaskHandle for BackgroundTaskHandle {
+        init(mut cx) {
+            match PENDING_BACKGROUND_TASK_HANDLE.with(|cell| cell.borrow_mut().take()) {
+                Some(handle) => Ok(handle),
+                None => {
+                    let message = cx.string(
+                        "JsBackgroundTaskHandle must be constructed via BackgroundTaskHandle::into_js",
+                    );
+                    cx.throw(message)
+                }
+            }
+        }
+
+        method pause(mut cx) {
+            let this = cx.this();
+            let guard = cx.lock();
+            this.borrow(&guard).pause();
+            Ok(cx.undefined().upcast())
+        }
+
+        method resume(mut cx) {
+            let this = cx.this();
+            let guard = cx.lock();
+            this.borrow(&guard).resume();
+            Ok(cx.undefined().upcast())
+        }
+
+        method flush(mut cx) {
+            let this = cx.this();
+            let guard = cx.lock();
+            this.borrow(&guard).flush();
+            Ok(cx.undefined().upcast())
+        }
+
+        method flushStop(mut cx) {
+            let this = cx.this();
+            let guard = cx.lock();
+            this.borrow(&guard).flush_stop();
+            Ok(cx.undefined().upcast())
+        }
+
+        method shutdown(mut cx) {
+            let this = cx.this();
+            let guard = cx.lock();
+            this.borrow(&guard).shutdown();
+            Ok(cx.undefined().upcast())
+        }
+
+        // The metrics/introspection surface: returns the same JS object
+        // shape as `metrics_to_js`, so test code can assert on exactly
+        // which side effects a call is still waiting for instead of
+        // scraping `println!` output.
+        method metrics(mut cx) {
+            let snapshot = {
+                let this = cx.this();
+                let guard = cx.lock();
+                this.borrow(&guard).metrics_snapshot()
+            };
+            let obj = metrics_to_js(&mut cx, &snapshot)?;
+            Ok(obj.upcast())
+        }
+    }
 }
 
 /// A singleton which runs in a Task and is the receiver for the Signal channel.
@@ -131,15 +615,108 @@ pub struct Waiter {
     checkers: HashMap<ZomeFnCall, CallFxChecker>,
     current: Option<ZomeFnCall>,
     sender_rx: Receiver<ControlSender>,
+    state: CheckerState,
+    /// `ActionWrapper`s received while `Paused`, replayed in order on `Resume`.
+    buffer: Vec<ActionWrapper>,
+    /// When `Pause` was applied, so `Resume` can push every checker's
+    /// `deadline` out by however long the pause lasted.
+    paused_at: Option<Instant>,
+    /// The root of the cancellation tree for this Waiter's checkers.
+    root_token: CancellationToken,
+    /// Shared with the JS-facing side so it can synchronously read a
+    /// snapshot of checker state without crossing into this Waiter's thread.
+    metrics: Arc<Mutex<WaiterMetrics>>,
 }
 
 impl Waiter {
-    pub fn new(sender_rx: Receiver<ControlSender>) -> Self {
+    pub fn new(sender_rx: Receiver<ControlSender>, root_token: CancellationToken) -> Self {
         Self {
             checkers: HashMap::new(),
             current: None,
             sender_rx,
+            state: CheckerState::Started,
+            buffer: Vec::new(),
+            paused_at: None,
+            root_token,
+            metrics: Arc::new(Mutex::new(WaiterMetrics {
+                lifecycle: "running".to_string(),
+                ..WaiterMetrics::default()
+            })),
+        }
+    }
+
+    /// A cloneable handle onto this Waiter's live metrics, for exposing to
+    /// the JS-facing side.
+    pub fn metrics_handle(&self) -> Arc<Mutex<WaiterMetrics>> {
+        self.metrics.clone()
+    }
+
+    /// Recompute the aggregate metrics from the current set of checkers.
+    /// Called any time `checkers` changes.
+    fn refresh_metrics(&mut self) {
+        let calls: Vec<CallMetrics> = self.checkers.values().map(CallFxChecker::metrics).collect();
+        let num_active_checkers = calls.len();
+        let num_conditions_outstanding = calls.iter().map(|c| c.num_conditions_outstanding).sum();
+        let oldest_pending_call_age_ms = calls.iter().map(|c| c.age_ms).max().unwrap_or(0);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.num_active_checkers = num_active_checkers;
+        metrics.num_conditions_outstanding = num_conditions_outstanding;
+        metrics.oldest_pending_call_age_ms = oldest_pending_call_age_ms;
+        metrics.calls = calls;
+    }
+
+    /// Record that a `Trigger` was rejected as invalid for the current
+    /// state, visible to the JS side instead of a colored `println!`.
+    fn record_invalid_trigger(&mut self, state: CheckerState, trigger: Trigger) {
+        self.metrics.lock().unwrap().last_invalid_trigger =
+            Some(format!("{:?} is not valid from {:?}", trigger, state));
+    }
+
+    /// Record a `MainBackgroundTask` lifecycle phase, visible to the JS
+    /// side instead of a plain `println!`.
+    fn set_lifecycle(&mut self, phase: &str) {
+        self.metrics.lock().unwrap().lifecycle = phase.to_string();
+    }
+
+    /// Apply a `Trigger` from the JS-facing side, moving `state` forward
+    /// and performing the side effects associated with the transition.
+    pub fn apply_trigger(&mut self, trigger: Trigger) -> Result<(), InvalidTransition> {
+        let next = advance(self.state, trigger)?;
+        match next {
+            CheckerState::Paused => {
+                self.state = next;
+                self.paused_at = Some(Instant::now());
+            }
+            CheckerState::Started if trigger == Trigger::Resume => {
+                self.state = next;
+                // A pause that lasted `elapsed` shouldn't cost in-flight
+                // checkers any of their timeout budget.
+                if let Some(paused_at) = self.paused_at.take() {
+                    let elapsed = paused_at.elapsed();
+                    for checker in self.checkers.values_mut() {
+                        checker.deadline += elapsed;
+                    }
+                }
+                let buffered: Vec<ActionWrapper> = self.buffer.drain(..).collect();
+                for aw in buffered {
+                    self.run_checks(&aw);
+                }
+            }
+            CheckerState::Flushing => {
+                self.state = next;
+                self.buffer.clear();
+                let calls: Vec<ZomeFnCall> = self.checkers.keys().cloned().collect();
+                for call in calls {
+                    if let Some(checker) = self.checkers.get_mut(&call) {
+                        checker.shutdown();
+                    }
+                }
+                self.checkers.clear();
+                self.refresh_metrics();
+            }
+            _ => self.state = next,
         }
+        Ok(())
     }
 
     pub fn process_signal(&mut self, sig: Signal) {
@@ -150,7 +727,8 @@ impl Waiter {
                     Action::ExecuteZomeFunction(call) => match self.sender_rx.try_recv() {
                         Ok(sender) => {
                             self.add_call(call.clone(), sender);
-                            self.current_checker().unwrap().add(move |aw| {
+                            let label = format!("waiting for ReturnZomeFunctionResult of {:?}", call);
+                            self.current_checker().unwrap().add(label, move |aw| {
                                 if let Action::ReturnZomeFunctionResult(ref r) = *aw.action() {
                                     r.call() == call
                                 } else {
@@ -160,7 +738,6 @@ impl Waiter {
                         }
                         Err(_) => {
                             self.deactivate_current();
-                            log("Waiter: deactivate_current");
                         }
                     },
 
@@ -169,7 +746,8 @@ impl Waiter {
                         // TODO: is there a possiblity that this can get messed up if the same
                         // entry is committed multiple times?
                         Some(checker) => {
-                            checker.add(move |aw| *aw.action() == Action::Hold(entry.clone()));
+                            let label = format!("waiting for Hold of {:?}", entry);
+                            checker.add(label, move |aw| *aw.action() == Action::Hold(entry.clone()));
                         }
                         None => (),
                     },
@@ -178,7 +756,9 @@ impl Waiter {
                         let msg_id = data.msg_id;
                         match (self.current_checker(), data.message) {
                             (Some(checker), DirectMessage::Custom(_)) => {
-                                checker.add(move |aw| {
+                                let label =
+                                    format!("waiting for direct message resolution of {}", msg_id);
+                                checker.add(label, move |aw| {
                                     [
                                         Action::ResolveDirectConnection(msg_id.clone()),
                                         Action::SendDirectMessageTimeout(msg_id.clone()),
@@ -190,6 +770,14 @@ impl Waiter {
                         }
                     }
 
+                    // If the zome call itself errored out, there's no point waiting
+                    // on its side effects any longer; cancel the checker outright.
+                    Action::ReturnZomeFunctionResult(ref response) => {
+                        if response.result().is_err() {
+                            self.cancel_call(&response.call());
+                        }
+                    }
+
                     _ => (),
                 };
 
@@ -201,16 +789,57 @@ impl Waiter {
     }
 
     fn run_checks(&mut self, aw: &ActionWrapper) {
-        let size = self.checkers.len();
+        if self.state == CheckerState::Paused {
+            self.buffer.push(aw.clone());
+            return;
+        }
         self.checkers.retain(|_, checker| checker.run_checks(aw));
-        if size != self.checkers.len() {
-            println!(
-                "\n{}: {} -> {}",
-                "Num checkers".italic(),
-                size,
-                self.checkers.len()
-            );
+        self.refresh_metrics();
+    }
+
+    /// Time out any checker whose deadline has passed, clearing its
+    /// conditions and notifying the waiting `CallBlockingTask`.
+    ///
+    /// A no-op while `Paused`: `deadline`s aren't frozen until `Resume`
+    /// pushes them out, so checking in the meantime would time out
+    /// checkers for time spent paused, not time spent actually waiting.
+    fn check_timeouts(&mut self) {
+        if self.state == CheckerState::Paused {
+            return;
         }
+        let now = Instant::now();
+        self.checkers.retain(|_, checker| {
+            if now >= checker.deadline {
+                checker.timeout();
+                false
+            } else {
+                true
+            }
+        });
+        self.refresh_metrics();
+    }
+
+    /// How long `MainBackgroundTask::perform` may safely block before it
+    /// next needs to call `check_timeouts()`, bounded above by `max_idle`
+    /// so `Trigger`s (which aren't registered with the `Select`) are still
+    /// picked up promptly even when no checker is close to timing out.
+    fn next_wake(&self, max_idle: Duration) -> Duration {
+        if self.state == CheckerState::Paused {
+            return max_idle;
+        }
+        let now = Instant::now();
+        self.checkers
+            .values()
+            .map(|checker| {
+                if checker.deadline > now {
+                    checker.deadline - now
+                } else {
+                    Duration::from_millis(0)
+                }
+            })
+            .min()
+            .unwrap_or(max_idle)
+            .min(max_idle)
     }
 
     fn current_checker(&mut self) -> Option<&mut CallFxChecker> {
@@ -220,11 +849,28 @@ impl Waiter {
     }
 
     fn add_call(&mut self, call: ZomeFnCall, tx: ControlSender) {
-        let checker = CallFxChecker::new(tx);
+        let token = self.root_token.child();
+        let checker =
+            CallFxChecker::new(tx, token, call.zome_name().to_string(), call.fn_name().to_string());
 
-        log("Waiter: add_call...");
         self.checkers.insert(call.clone(), checker);
         self.current = Some(call);
+        self.refresh_metrics();
+    }
+
+    /// Cancel a single in-flight checker, e.g. because its `ZomeFnCall` errored.
+    pub fn cancel_call(&mut self, call: &ZomeFnCall) {
+        if let Some(mut checker) = self.checkers.remove(call) {
+            checker.cancel();
+        }
+        self.refresh_metrics();
+    }
+
+    /// Cancel every in-flight checker at once, e.g. on container shutdown.
+    pub fn cancel_all(&mut self) {
+        self.root_token.cancel();
+        self.checkers.clear();
+        self.refresh_metrics();
     }
 
     fn deactivate_current(&mut self) {
@@ -236,26 +882,59 @@ impl Waiter {
 /// It runs in a Node worker thread, receiving Signals and running them through
 /// the Waiter. Each TestContainer spawns its own MainBackgroundTask.
 pub struct MainBackgroundTask {
-    /// The Receiver<Signal> for the Container
-    signal_rx: SignalReceiver,
+    /// Signals, forwarded from `signal_rx` by a helper thread so perform()
+    /// can `Select` over them alongside `shutdown_rx` instead of polling.
+    signal_rx: SignalEventReceiver,
+    /// Its own channel, independent of `signal_rx`, so a `shutdown()` call
+    /// is never stuck behind whatever Signals are already queued.
+    shutdown_rx: ShutdownReceiver,
     /// The Waiter is in a RefCell because perform() uses an immutable &self reference
     waiter: RefCell<Waiter>,
-    /// This Mutex is flipped from true to false from within the TestContainer
-    is_running: Arc<Mutex<bool>>,
+    /// Pause/resume/flush commands from the JS-facing side, applied to the Waiter
+    trigger_rx: TriggerReceiver,
 }
 
 impl MainBackgroundTask {
+    /// Constructs the task along with a `BackgroundTaskHandle` that the
+    /// JS-facing bindings use to pause, resume, flush, or shut down the
+    /// signal Waiter while this task is running.
     pub fn new(
         signal_rx: SignalReceiver,
         sender_rx: Receiver<ControlSender>,
-        is_running: Arc<Mutex<bool>>,
-    ) -> Self {
+        root_token: CancellationToken,
+    ) -> (Self, BackgroundTaskHandle) {
+        let (signal_event_tx, signal_event_rx) = crossbeam_channel::bounded(64);
+        let (shutdown_tx, shutdown_rx) = crossbeam_channel::bounded(1);
+        let (trigger_tx, trigger_rx) = sync_channel(8);
+
+        // `signal_rx` is a std::sync::mpsc receiver owned by holochain_core,
+        // so it can't be registered directly with a crossbeam `Select`;
+        // forward it onto a crossbeam channel with a single dedicated
+        // thread instead of polling it from perform().
+        thread::spawn(move || {
+            while let Ok(sig) = signal_rx.recv() {
+                if signal_event_tx.send(sig).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let waiter = Waiter::new(sender_rx, root_token);
+        let metrics = waiter.metrics_handle();
         let this = Self {
-            signal_rx,
-            waiter: RefCell::new(Waiter::new(sender_rx)),
-            is_running,
+            signal_rx: signal_event_rx,
+            shutdown_rx,
+            waiter: RefCell::new(waiter),
+            trigger_rx,
         };
-        this
+        (
+            this,
+            BackgroundTaskHandle {
+                trigger_tx,
+                shutdown_tx,
+                metrics,
+            },
+        )
     }
 }
 
@@ -265,22 +944,61 @@ impl Task for MainBackgroundTask {
     type JsEvent = JsUndefined;
 
     fn perform(&self) -> Result<(), String> {
-        while *self.is_running.lock().unwrap() {
-            // TODO: could use channels more intelligently to stop immediately
-            // rather than waiting for timeout, but it's more complicated and probably
-            // involves adding some kind of control variant to the Signal enum
-            match self.signal_rx.recv_timeout(Duration::from_millis(250)) {
-                Ok(sig) => self.waiter.borrow_mut().process_signal(sig),
-                Err(RecvTimeoutError::Timeout) => continue,
-                Err(err) => return Err(err.to_string()),
+        // The idle fallback when nothing else bounds the wake-up: no
+        // checker is ever more than this far from getting its Trigger
+        // backlog drained, even while none is close to timing out.
+        const MAX_IDLE: Duration = Duration::from_millis(250);
+
+        let mut select = Select::new();
+        let shutdown_idx = select.recv(&self.shutdown_rx);
+        let signal_idx = select.recv(&self.signal_rx);
+
+        loop {
+            // A pending Shutdown takes priority over queued Signals: `Select`
+            // picks at random among ready operations.
+            if self.shutdown_rx.try_recv().is_ok() {
+                break;
+            }
+            // Block on whichever of Signal/Shutdown is ready, waking up no
+            // later than the soonest checker deadline (or MAX_IDLE, if
+            // that's sooner) so check_timeouts() fires right when it needs
+            // to rather than on a fixed poll. Shutdown has its own
+            // operation/channel, so it's picked up as soon as it's sent
+            // rather than waiting behind however many Signals are queued.
+            let wake_in = self.waiter.borrow().next_wake(MAX_IDLE);
+            match select.select_timeout(wake_in) {
+                Ok(op) if op.index() == shutdown_idx => {
+                    let _ = op.recv(&self.shutdown_rx);
+                    break;
+                }
+                Ok(op) if op.index() == signal_idx => {
+                    match op.recv(&self.signal_rx) {
+                        Ok(sig) => self.waiter.borrow_mut().process_signal(sig),
+                        Err(_) => return Err("signal channel disconnected".to_string()),
+                    }
+                }
+                Ok(_) => unreachable!("no other operations are registered"),
+                Err(_) => (),
             }
+
+            while let Ok(trigger) = self.trigger_rx.try_recv() {
+                let mut waiter = self.waiter.borrow_mut();
+                if let Err(InvalidTransition(state, trigger)) = waiter.apply_trigger(trigger) {
+                    waiter.record_invalid_trigger(state, trigger);
+                }
+            }
+            // On every wake, whether from a Signal or the computed
+            // deadline-driven timeout, check whether any checker's
+            // deadline has passed.
+            self.waiter.borrow_mut().check_timeouts();
         }
 
-        for (_, checker) in self.waiter.borrow_mut().checkers.iter_mut() {
-            println!("{}", "Shutting down lingering checker...".magenta().bold());
-            checker.shutdown();
+        {
+            let mut waiter = self.waiter.borrow_mut();
+            waiter.set_lifecycle("cancelling_lingering_checkers");
+            waiter.cancel_all();
+            waiter.set_lifecycle("stopped");
         }
-        println!("Terminating MainBackgroundTask::perform() loop");
         Ok(())
     }
 
@@ -289,7 +1007,7 @@ impl Task for MainBackgroundTask {
             let error_string = cx.string(format!("unable to shut down background task: {}", e));
             cx.throw(error_string)
         })?;
-        println!("MainBackgroundTask shut down");
+        self.waiter.borrow_mut().set_lifecycle("shut_down");
         Ok(cx.undefined())
     }
 }
@@ -335,13 +1053,86 @@ mod tests {
     fn test_waiter() -> (Waiter, Receiver<ControlMsg>) {
         let (sender_tx, sender_rx) = sync_channel(0);
         let (control_tx, control_rx) = sync_channel(0);
-        let waiter = Waiter::new(sender_rx);
+        let waiter = Waiter::new(sender_rx, CancellationToken::new());
         sender_tx
             .send(control_tx)
             .expect("Could not send control sender");
         (waiter, control_rx)
     }
 
+    #[test]
+    fn advance_rejects_transitions_that_do_not_make_sense_for_the_state() {
+        assert_eq!(
+            advance(CheckerState::Started, Trigger::Resume),
+            Err(InvalidTransition(CheckerState::Started, Trigger::Resume))
+        );
+        assert_eq!(
+            advance(CheckerState::Paused, Trigger::Pause),
+            Err(InvalidTransition(CheckerState::Paused, Trigger::Pause))
+        );
+        assert_eq!(
+            advance(CheckerState::Flushing, Trigger::Pause),
+            Err(InvalidTransition(CheckerState::Flushing, Trigger::Pause))
+        );
+        assert_eq!(
+            advance(CheckerState::Stopped, Trigger::Stop),
+            Err(InvalidTransition(CheckerState::Stopped, Trigger::Stop))
+        );
+    }
+
+    #[test]
+    fn pause_buffers_signals_and_resume_replays_them_in_order() {
+        let (mut waiter, control_rx) = test_waiter();
+        let entry = mk_entry("t1", "x");
+        let call = zf_call("c1");
+
+        waiter.process_signal(sig(ExecuteZomeFunction(call.clone())));
+        waiter
+            .apply_trigger(Trigger::Pause)
+            .expect("Pause should be a valid transition from Started");
+
+        waiter.process_signal(sig(Commit((entry.clone(), None))));
+        waiter.process_signal(sig(Hold(entry)));
+        waiter.process_signal(sig(ReturnZomeFunctionResult(zf_response(call))));
+        assert!(
+            control_rx.try_recv().is_err(),
+            "ControlMsg::Stop received while Paused; signals should have been buffered"
+        );
+
+        waiter
+            .apply_trigger(Trigger::Resume)
+            .expect("Resume should be a valid transition from Paused");
+        assert!(
+            control_rx.try_recv().is_ok(),
+            "ControlMsg::Stop not received after Resume replayed the buffered signals"
+        );
+    }
+
+    #[test]
+    fn cancelling_a_root_token_cascades_to_children_and_grandchildren() {
+        let root = CancellationToken::new();
+        let child = root.child();
+        let grandchild = child.child();
+
+        assert!(!child.is_cancelled());
+        assert!(!grandchild.is_cancelled());
+
+        root.cancel();
+
+        assert!(child.is_cancelled());
+        assert!(grandchild.is_cancelled());
+    }
+
+    #[test]
+    fn a_child_created_after_its_parent_is_cancelled_is_cancelled_immediately() {
+        let root = CancellationToken::new();
+        root.cancel();
+
+        let child = root.child();
+
+        assert!(child.is_cancelled());
+    }
+
     #[test]
     fn can_await_commit_simple() {
         let (mut waiter, control_rx) = test_waiter();
@@ -393,6 +1184,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn call_checker_times_out_after_its_deadline_passes() {
+        let (mut waiter, control_rx) = test_waiter();
+        let call = zf_call("slow");
+
+        waiter.process_signal(sig(ExecuteZomeFunction(call.clone())));
+        waiter
+            .checkers
+            .get_mut(&call)
+            .expect("a checker should have been created for the call")
+            .deadline = Instant::now();
+
+        waiter.check_timeouts();
+
+        match control_rx.try_recv() {
+            Ok(ControlMsg::Timeout { num_conditions, .. }) => assert_eq!(num_conditions, 1),
+            Ok(ControlMsg::Stop) => panic!("expected ControlMsg::Timeout, got Stop"),
+            Ok(ControlMsg::Cancelled) => panic!("expected ControlMsg::Timeout, got Cancelled"),
+            Err(_) => panic!("expected ControlMsg::Timeout, got nothing"),
+        }
+    }
+
     #[test]
     fn can_await_direct_messages() {
         let (mut waiter, control_rx) = test_waiter();